@@ -1,16 +1,22 @@
-use std::{env, path::Path, fs};
+use std::{env, path::Path, fs, process};
 
-use css::{lex, parse};
+use css::{lex, StyleFormat, Stylesheet};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let path = Path::new(&args[1]);
     let input = fs::read_to_string(path).unwrap();
-    if let Ok(tokens) = lex(input) {
-        let s_exprs = parse(tokens);
-        println!("{:?}", s_exprs);
-        for s_expr in s_exprs {
-            print!("{}", s_expr.to_stylesheet(""));
+    let tokens = lex(&input);
+    match Stylesheet::parse(&tokens) {
+        Ok(stylesheet) => {
+            println!("{:?}", stylesheet);
+            print!("{}", stylesheet.to_stylesheet(StyleFormat::Pretty));
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error.render(&input));
+            }
+            process::exit(1);
         }
     }
-}
\ No newline at end of file
+}
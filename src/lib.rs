@@ -1,70 +1,310 @@
-use std::{iter::Peekable, slice::Iter, str::Chars};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::{iter::Peekable, slice::Iter, str::CharIndices};
 use wasm_bindgen::prelude::*;
 
+/// A half-open byte range `[start, end)` into the original source string.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, PartialEq, Eq)]
-pub enum Token {
-    String(String),
+pub enum TokenKind<'s> {
+    String(&'s str),
     LParen,
     RParen,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Selector(String);
+/// A lexed token together with the byte range it occupied in the source.
+///
+/// Every lexeme borrows directly from the source `&'s str`, so lexing a
+/// stylesheet performs no per-token heap allocation.
+#[derive(Debug)]
+pub struct Token<'s> {
+    pub kind: TokenKind<'s>,
+    pub span: Span,
+}
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Rule {
-    property: String,
-    value: Vec<String>,
+// Spans are positional metadata, not part of a token's identity; comparing
+// tokens (as the tests do) should only care about the lexeme.
+impl<'s> PartialEq for Token<'s> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+impl<'s> Eq for Token<'s> {}
+
+#[derive(Debug)]
+pub struct Selector<'s> {
+    name: &'s str,
+}
+
+impl<'s> PartialEq for Selector<'s> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+impl<'s> Eq for Selector<'s> {}
+
+#[derive(Debug)]
+pub struct Rule<'s> {
+    property: &'s str,
+    value: Vec<&'s str>,
+}
+
+impl<'s> PartialEq for Rule<'s> {
+    fn eq(&self, other: &Self) -> bool {
+        self.property == other.property && self.value == other.value
+    }
+}
+impl<'s> Eq for Rule<'s> {}
+
+impl<'s> Rule<'s> {
+    /// Render the value tokens into a space-joined string, expanding any
+    /// `$--name` or `(var --name)` references against `vars`. An unknown
+    /// reference is left verbatim so the defect is visible in the output.
+    fn value_string(&self, vars: &VarTable<'s>) -> String {
+        let mut resolved: Vec<&'s str> = vec![];
+        let mut tokens = self.value.iter();
+        while let Some(&token) = tokens.next() {
+            if let Some(name) = token.strip_prefix('$') {
+                match vars.get(name) {
+                    Some(substitution) => resolved.extend(substitution.iter().copied()),
+                    None => resolved.push(token),
+                }
+            } else if token == "var" {
+                match tokens.next() {
+                    Some(&name) => match vars.get(name) {
+                        Some(substitution) => resolved.extend(substitution.iter().copied()),
+                        None => resolved.extend([token, name]),
+                    },
+                    None => resolved.push(token),
+                }
+            } else {
+                resolved.push(token);
+            }
+        }
+        resolved.join(" ")
+    }
+}
+
+#[derive(Debug)]
+pub struct SExpr<'s> {
+    selector: Selector<'s>,
+    rules: Vec<Rule<'s>>,
+    children: Vec<SExpr<'s>>,
+    span: Span,
+}
+
+impl<'s> PartialEq for SExpr<'s> {
+    fn eq(&self, other: &Self) -> bool {
+        self.selector == other.selector
+            && self.rules == other.rules
+            && self.children == other.children
+    }
 }
+impl<'s> Eq for SExpr<'s> {}
 
+/// A structural error discovered while parsing, carrying a human-readable
+/// message and the byte span of the offending input.
 #[derive(Debug, PartialEq, Eq)]
-pub struct SExpr {
-    selector: Selector,
-    rules: Vec<Rule>,
-    children: Vec<SExpr>,
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ParseError {
+    /// Render this error against the `source` it was produced from, resolving
+    /// the span to a one-based line and column, e.g.
+    /// ``line 3, col 12: expected a value after property `color` ``.
+    pub fn render(&self, source: &str) -> String {
+        let (line, col) = line_col(source, self.span.start);
+        format!("line {}, col {}: {}", line, col, self.message)
+    }
 }
 
-impl SExpr {
-    pub fn to_stylesheet(&self, parent: &str) -> String {
-        let selector = self.selector.0.split_inclusive(",").map(|s| format!("{} {}", parent, s)).collect::<Vec<String>>().join("\n");
-        let rules: Vec<String> = self.rules.iter().map(|rule| format!("    {}: {};", rule.property, rule.value.join(" "))).collect();
-        let children: Vec<String> = self.children.iter().map(|child| child.to_stylesheet(&selector)).collect();
-        if self.rules.is_empty() {
-            format!("{}", children.join(""))
+/// Convert a byte `offset` into a one-based `(line, column)` pair by scanning
+/// the source for newlines up to that offset.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut col = 1;
+    for (i, c) in source.char_indices() {
+        if i >= offset {
+            break;
+        }
+        if c == '\n' {
+            line += 1;
+            col = 1;
         } else {
-            format!("{} {{\n{}\n}}\n{}", selector, rules.join("\n"), children.join("\n"))
+            col += 1;
         }
     }
+    (line, col)
 }
 
-pub fn lex(input: String) -> Result<Vec<Token>, String> {
+/// Layout used when serialising a stylesheet.
+#[wasm_bindgen]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StyleFormat {
+    /// Human-readable output: four-space indents, one declaration per line.
+    #[default]
+    Pretty,
+    /// Production output with every insignificant byte of whitespace removed.
+    Minified,
+}
+
+/// A collection of `--name` definitions looked up while substituting `var`
+/// references into rule values.
+type VarTable<'s> = HashMap<&'s str, Vec<&'s str>>;
+
+impl<'s> SExpr<'s> {
+    /// Emit the compiled CSS for this node under `parent`, appending into the
+    /// shared `out` buffer so a whole stylesheet reuses a single allocation.
+    pub fn to_stylesheet(&self, parent: &str, format: StyleFormat, out: &mut String) {
+        self.to_stylesheet_with_vars(parent, format, &VarTable::new(), out);
+    }
+
+    /// The byte range this S-expression occupies in the source, from its
+    /// opening paren to its closing one.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The deepest nested S-expression whose span contains `offset`, if any.
+    pub fn find_at(&self, offset: usize) -> Option<&SExpr<'s>> {
+        if offset < self.span.start || offset >= self.span.end {
+            return None;
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find_at(offset))
+            .or(Some(self))
+    }
+
+    /// Like [`to_stylesheet`](Self::to_stylesheet) but resolves `$--name` and
+    /// `(var --name)` references in rule values against `vars`.
+    fn to_stylesheet_with_vars(&self, parent: &str, format: StyleFormat, vars: &VarTable<'s>, out: &mut String) {
+        let selector = self.selector_for(parent, format);
+        match format {
+            StyleFormat::Pretty => {
+                if self.rules.is_empty() {
+                    for child in &self.children {
+                        child.to_stylesheet_with_vars(&selector, format, vars, out);
+                    }
+                } else {
+                    let _ = write!(out, "{} {{", selector);
+                    out.push('\n');
+                    for (i, rule) in self.rules.iter().enumerate() {
+                        if i > 0 {
+                            out.push('\n');
+                        }
+                        let _ = write!(out, "    {}: {};", rule.property, rule.value_string(vars));
+                    }
+                    out.push_str("\n}\n");
+                    for (i, child) in self.children.iter().enumerate() {
+                        if i > 0 {
+                            out.push('\n');
+                        }
+                        child.to_stylesheet_with_vars(&selector, format, vars, out);
+                    }
+                }
+            }
+            StyleFormat::Minified => {
+                if !self.rules.is_empty() {
+                    let _ = write!(out, "{}{{", selector);
+                    for (i, rule) in self.rules.iter().enumerate() {
+                        if i > 0 {
+                            out.push(';');
+                        }
+                        let _ = write!(out, "{}:{}", rule.property, rule.value_string(vars));
+                    }
+                    out.push('}');
+                }
+                for child in &self.children {
+                    child.to_stylesheet_with_vars(&selector, format, vars, out);
+                }
+            }
+        }
+    }
+
+    /// Compute this node's full selector under `parent`, expanding each
+    /// comma-separated alternative against every parent alternative. A child
+    /// alternative that begins with `&` attaches directly to the parent
+    /// (e.g. `&:hover` under `a` yields `a:hover`); otherwise the parent and
+    /// child are joined with a descendant-combinator space.
+    fn selector_for(&self, parent: &str, format: StyleFormat) -> String {
+        let parents = parent_alternatives(parent);
+        let mut alternatives: Vec<String> = vec![];
+        for child in self.selector.name.split(',') {
+            let child = child.trim();
+            if child.is_empty() {
+                continue;
+            }
+            match child.strip_prefix('&') {
+                Some(rest) if !parents.is_empty() => {
+                    for parent in &parents {
+                        alternatives.push(format!("{}{}", parent, rest));
+                    }
+                }
+                Some(rest) => alternatives.push(rest.to_owned()),
+                None if parents.is_empty() => alternatives.push(child.to_owned()),
+                None => {
+                    for parent in &parents {
+                        alternatives.push(format!("{} {}", parent, child));
+                    }
+                }
+            }
+        }
+        let separator = match format {
+            StyleFormat::Pretty => ",\n",
+            StyleFormat::Minified => ",",
+        };
+        alternatives.join(separator)
+    }
+}
+
+/// Split a computed parent selector back into its individual alternatives,
+/// dropping the layout whitespace that [`SExpr::selector_for`] inserts between
+/// them.
+fn parent_alternatives(parent: &str) -> Vec<&str> {
+    parent
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|alternative| !alternative.is_empty())
+        .collect()
+}
+
+pub fn lex(input: &str) -> Vec<Token<'_>> {
     let mut tokens = vec![];
-    let mut chars = input.chars().peekable();
-    while let Some(c) = chars.peek() {
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
         let token = match c {
             '(' => {
                 chars.next();
-                Token::LParen
+                Token { kind: TokenKind::LParen, span: Span { start: i, end: i + 1 } }
             }
             ')' => {
                 chars.next();
-                Token::RParen
+                Token { kind: TokenKind::RParen, span: Span { start: i, end: i + 1 } }
             }
             c if c.is_whitespace() => {
                 chars.next();
                 continue;
             }
-            _ => Token::String(lex_string(&mut chars)),
+            _ => lex_string(&mut chars, input),
         };
         tokens.push(token);
     }
-    Ok(tokens)
+    tokens
 }
 
-fn lex_string(chars: &mut Peekable<Chars>) -> String {
-    let mut string = String::new();
+fn lex_string<'s>(chars: &mut Peekable<CharIndices<'s>>, input: &'s str) -> Token<'s> {
+    let start = chars.peek().map(|&(i, _)| i).unwrap_or(0);
+    let mut end = start;
     let mut depth: u32 = 0;
-    while let Some(c) = chars.peek() {
+    while let Some(&(i, c)) = chars.peek() {
         match c {
             '(' => depth += 1,
             ')' if depth > 0 => depth -= 1,
@@ -72,111 +312,324 @@ fn lex_string(chars: &mut Peekable<Chars>) -> String {
             c if c.is_whitespace() && depth == 0 => break,
             _ => (),
         };
-        string.push(*c);
+        end = i + c.len_utf8();
         chars.next();
     }
-    string
+    Token {
+        kind: TokenKind::String(&input[start..end]),
+        span: Span { start, end },
+    }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Vec<SExpr> {
+pub fn parse<'s>(tokens: &[Token<'s>]) -> Result<Vec<SExpr<'s>>, Vec<ParseError>> {
     let mut s_exprs = vec![];
+    let mut errors = vec![];
     let mut left = 0;
     let mut depth: u32 = 0;
     for (right, token) in tokens.iter().enumerate() {
-        match token {
-            Token::LParen => depth += 1,
-            Token::RParen => depth -= 1,
+        match token.kind {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen => depth = depth.saturating_sub(1),
             _ => (),
         };
         if depth == 0 {
-            if let Some(s_expr) = parse_s_expr(&mut tokens[left + 1..right].iter().peekable()) {
-                s_exprs.push(s_expr);
+            if right < left + 1 {
+                // A surplus closing paren: depth was already 0, so this
+                // token doesn't close anything `left` opened. A bare string
+                // outside any group lands here too.
+                let message = match token.kind {
+                    TokenKind::RParen => "unexpected closing parenthesis",
+                    _ => "expected an opening parenthesis",
+                };
+                errors.push(ParseError {
+                    message: message.to_owned(),
+                    span: token.span,
+                });
+                left = right + 1;
+                continue;
+            }
+            let group = Span {
+                start: tokens[left].span.start,
+                end: token.span.end,
+            };
+            match parse_s_expr(&mut tokens[left + 1..right].iter().peekable(), group) {
+                Ok(s_expr) => s_exprs.push(s_expr),
+                Err(error) => errors.push(error),
             }
             left = right + 1;
         }
     }
-    s_exprs
+    if depth != 0 {
+        let span = tokens.get(left).map(|token| token.span).unwrap_or_default();
+        errors.push(ParseError {
+            message: "unterminated parenthesis group".to_owned(),
+            span,
+        });
+    }
+    if errors.is_empty() {
+        Ok(s_exprs)
+    } else {
+        Err(errors)
+    }
 }
 
-fn parse_s_expr(tokens: &mut Peekable<Iter<Token>>) -> Option<SExpr> {
-    let selector = match tokens.next()? {
-        Token::String(string) => Selector(string.to_owned()),
-        _ => return None,
+fn parse_s_expr<'a, 's>(tokens: &mut Peekable<Iter<'a, Token<'s>>>, group: Span) -> Result<SExpr<'s>, ParseError> {
+    let selector = match tokens.next() {
+        Some(Token { kind: TokenKind::String(string), .. }) => Selector { name: string },
+        Some(token) => {
+            return Err(ParseError {
+                message: "expected a selector".to_owned(),
+                span: token.span,
+            })
+        }
+        None => {
+            return Err(ParseError {
+                message: "expected a selector".to_owned(),
+                span: group,
+            })
+        }
     };
+    // The group's byte range runs from its opening paren to its closing one;
+    // `group.end` already covers a well-formed top-level group, and a nested
+    // group's closing paren is picked up by the default arm below.
+    let mut end = group.end;
     let mut rules = vec![];
     let mut children = vec![];
     while let Some(token) = tokens.peek() {
-        match token {
-            Token::String(_) => rules.push(parse_rule(tokens)?),
-            Token::LParen => {
-                tokens.next();
-                children.push(parse_s_expr(tokens)?);
+        match token.kind {
+            TokenKind::String(_) => rules.push(parse_rule(tokens)?),
+            TokenKind::LParen => {
+                let open = tokens.next().expect("peeked").span;
+                children.push(parse_s_expr(tokens, open)?);
             }
             _ => {
+                end = token.span.end;
                 tokens.next();
                 break;
             }
         };
     }
-    Some(SExpr {
+    Ok(SExpr {
         selector,
         rules,
         children,
+        span: Span { start: group.start, end },
     })
 }
 
-fn parse_rule(tokens: &mut Peekable<Iter<Token>>) -> Option<Rule> {
-    let property = match tokens.next()? {
-        Token::String(string) => string.to_owned(),
-        _ => return None,
+fn parse_rule<'a, 's>(tokens: &mut Peekable<Iter<'a, Token<'s>>>) -> Result<Rule<'s>, ParseError> {
+    let (property, span) = match tokens.next() {
+        Some(Token { kind: TokenKind::String(string), span }) => (*string, *span),
+        Some(token) => {
+            return Err(ParseError {
+                message: "expected a property".to_owned(),
+                span: token.span,
+            })
+        }
+        None => {
+            return Err(ParseError {
+                message: "expected a property".to_owned(),
+                span: Span::default(),
+            })
+        }
     };
-    let value = match tokens.next()? {
-        Token::String(value) => vec![value.to_owned()],
-        Token::LParen => tokens
-            .map_while(|token| match token {
-                Token::String(string) => Some(string.to_owned()),
-                _ => None,
+    let value = match tokens.next() {
+        Some(Token { kind: TokenKind::String(value), .. }) => vec![*value],
+        Some(Token { kind: TokenKind::LParen, .. }) => {
+            // A value list may itself contain nested groups, e.g. the `(var
+            // --name)` reference form inside `(1px solid (var --brand))`.
+            // Track depth so a nested group's tokens fold into the same
+            // flat value list instead of being left for the caller to
+            // re-parse as a sibling rule.
+            let mut depth: u32 = 1;
+            let mut values = vec![];
+            for token in tokens.by_ref() {
+                match &token.kind {
+                    TokenKind::LParen => depth += 1,
+                    TokenKind::RParen => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    TokenKind::String(string) => values.push(*string),
+                }
+            }
+            values
+        }
+        _ => {
+            return Err(ParseError {
+                message: format!("expected a value after property `{}`", property),
+                span,
             })
-            .collect::<Vec<String>>(),
-        _ => return None,
+        }
     };
     let rule = Rule { property, value };
-    Some(rule)
+    Ok(rule)
+}
+
+/// A parsed sheet: its `(defvar ...)` definitions, its rule-bearing
+/// S-expressions, and an optional `parent` sheet it inherits from. A child's
+/// definitions and rules are layered on top of the parent's (child wins).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stylesheet<'s> {
+    vars: VarTable<'s>,
+    s_exprs: Vec<SExpr<'s>>,
+    parent: Option<Box<Stylesheet<'s>>>,
+}
+
+impl<'s> Stylesheet<'s> {
+    /// Parse `tokens`, hoisting each top-level `(defvar --name value...)` form
+    /// into the symbol table and keeping the remaining S-expressions as rules.
+    pub fn parse(tokens: &[Token<'s>]) -> Result<Stylesheet<'s>, Vec<ParseError>> {
+        let mut vars = VarTable::new();
+        let mut s_exprs = vec![];
+        for s_expr in parse(tokens)? {
+            if s_expr.selector.name == "defvar" {
+                if let Some(rule) = s_expr.rules.into_iter().next() {
+                    vars.insert(rule.property, rule.value);
+                }
+            } else {
+                s_exprs.push(s_expr);
+            }
+        }
+        Ok(Stylesheet {
+            vars,
+            s_exprs,
+            parent: None,
+        })
+    }
+
+    /// Layer this sheet on top of `base`, so `base`'s definitions and rules are
+    /// emitted underneath this sheet's.
+    pub fn with_base(mut self, base: Stylesheet<'s>) -> Stylesheet<'s> {
+        self.parent = Some(Box::new(base));
+        self
+    }
+
+    /// Merge the definitions along the parent chain, with nearer sheets
+    /// overriding those they inherit from.
+    fn resolve(&self) -> VarTable<'s> {
+        let mut vars = match &self.parent {
+            Some(parent) => parent.resolve(),
+            None => VarTable::new(),
+        };
+        for (name, value) in &self.vars {
+            vars.insert(name, value.clone());
+        }
+        vars
+    }
+
+    /// The rule-bearing S-expressions of the whole chain, parent first so a
+    /// child's rules cascade over the base's.
+    fn all_rules(&self) -> Vec<&SExpr<'s>> {
+        let mut rules = match &self.parent {
+            Some(parent) => parent.all_rules(),
+            None => vec![],
+        };
+        rules.extend(self.s_exprs.iter());
+        rules
+    }
+
+    /// Emit the resolved stylesheet, substituting variable references.
+    pub fn to_stylesheet(&self, format: StyleFormat) -> String {
+        let vars = self.resolve();
+        let mut out = String::new();
+        for s_expr in self.all_rules() {
+            s_expr.to_stylesheet_with_vars("", format, &vars, &mut out);
+        }
+        out
+    }
+
+    /// Render the compiled CSS of the deepest S-expression containing
+    /// `offset`, substituting this sheet's resolved variables. Used by the
+    /// language server to preview a node's expanded CSS on hover.
+    pub fn render_at(&self, offset: usize, format: StyleFormat) -> Option<String> {
+        let vars = self.resolve();
+        let mut s_expr = self.all_rules().into_iter().find(|s_expr| s_expr.find_at(offset).is_some())?;
+        // Walk down to the deepest node, accumulating its ancestors' selector
+        // so a nested node renders as it does in the full stylesheet.
+        let mut parent = String::new();
+        while let Some(child) = s_expr.children.iter().find(|child| child.find_at(offset).is_some()) {
+            parent = s_expr.selector_for(&parent, format);
+            s_expr = child;
+        }
+        let mut out = String::new();
+        s_expr.to_stylesheet_with_vars(&parent, format, &vars, &mut out);
+        Some(out)
+    }
+}
+
+#[wasm_bindgen]
+pub fn string_to_stylesheet(input: String, format: StyleFormat) -> Result<String, JsValue> {
+    let tokens = lex(&input);
+    let stylesheet = Stylesheet::parse(&tokens).map_err(|errors| render_errors(&errors, &input))?;
+    Ok(stylesheet.to_stylesheet(format))
 }
 
 #[wasm_bindgen]
-pub fn string_to_stylesheet(input: String) -> Result<String, JsValue> {
-    let mut string = String::new();
-    let tokens = lex(input)?;
-    let s_exprs = parse(tokens);
-    for s_expr in s_exprs {
-        string += &s_expr.to_stylesheet("");
-    }
-    Ok(string)
+pub fn string_to_stylesheet_with_base(child: String, base: String, format: StyleFormat) -> Result<String, JsValue> {
+    let base_tokens = lex(&base);
+    let base_sheet = Stylesheet::parse(&base_tokens).map_err(|errors| render_errors(&errors, &base))?;
+    let child_tokens = lex(&child);
+    let child_sheet = Stylesheet::parse(&child_tokens).map_err(|errors| render_errors(&errors, &child))?;
+    Ok(child_sheet.with_base(base_sheet).to_stylesheet(format))
+}
+
+fn render_errors(errors: &[ParseError], source: &str) -> JsValue {
+    let rendered: Vec<String> = errors.iter().map(|error| error.render(source)).collect();
+    JsValue::from_str(&rendered.join("\n"))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{lex, parse, Rule, SExpr, Selector, Token};
+    use crate::{lex, line_col, parse, ParseError, Rule, SExpr, Selector, Span, Token, TokenKind};
+
+    fn string(string: &str) -> Token<'_> {
+        Token { kind: TokenKind::String(string), span: Span::default() }
+    }
+
+    fn lparen<'s>() -> Token<'s> {
+        Token { kind: TokenKind::LParen, span: Span::default() }
+    }
+
+    fn rparen<'s>() -> Token<'s> {
+        Token { kind: TokenKind::RParen, span: Span::default() }
+    }
+
+    fn selector(name: &str) -> Selector<'_> {
+        Selector { name }
+    }
+
+    fn rule<'s>(property: &'s str, value: &[&'s str]) -> Rule<'s> {
+        Rule {
+            property,
+            value: value.to_vec(),
+        }
+    }
+
+    fn s_expr<'s>(selector: Selector<'s>, rules: Vec<Rule<'s>>, children: Vec<SExpr<'s>>) -> SExpr<'s> {
+        SExpr { selector, rules, children, span: Span::default() }
+    }
 
     #[test]
     fn lex_selector_empty() {
         let input = "(body)";
-        let tokens = lex(input.into()).unwrap();
-        let expected = vec![Token::LParen, Token::String("body".into()), Token::RParen];
+        let tokens = lex(input);
+        let expected = vec![lparen(), string("body"), rparen()];
         assert_eq!(tokens, expected);
     }
 
     #[test]
     fn lex_selector_property_value() {
         let input = "(body color red)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("color".into()),
-            Token::String("red".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("color"),
+            string("red"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -184,29 +637,26 @@ mod tests {
     #[test]
     fn parse_selector_property_value() {
         let input = "(body color red)";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("body".into()),
-            rules: vec![Rule {
-                property: "color".into(),
-                value: vec!["red".into()],
-            }],
-            children: vec![],
-        }];
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("body"),
+            vec![rule("color", &["red"])],
+            vec![],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn lex_selector_hyphenated_property_value() {
         let input = "(body background-color red)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("background-color".into()),
-            Token::String("red".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("background-color"),
+            string("red"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -214,18 +664,18 @@ mod tests {
     #[test]
     fn lex_selector_property_list_value() {
         let input = "(body margin (0 8px 0 8px))";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("margin".into()),
-            Token::LParen,
-            Token::String("0".into()),
-            Token::String("8px".into()),
-            Token::String("0".into()),
-            Token::String("8px".into()),
-            Token::RParen,
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("margin"),
+            lparen(),
+            string("0"),
+            string("8px"),
+            string("0"),
+            string("8px"),
+            rparen(),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -233,29 +683,26 @@ mod tests {
     #[test]
     fn parse_selector_property_list_value() {
         let input = "(body margin (0 8px 0 8px))";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("body".into()),
-            rules: vec![Rule {
-                property: "margin".into(),
-                value: vec!["0".into(), "8px".into(), "0".into(), "8px".into()],
-            }],
-            children: vec![],
-        }];
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("body"),
+            vec![rule("margin", &["0", "8px", "0", "8px"])],
+            vec![],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn lex_selector_property_alphanumeric_value() {
         let input = "(body font-size 14px)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("font-size".into()),
-            Token::String("14px".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("font-size"),
+            string("14px"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -263,15 +710,15 @@ mod tests {
     #[test]
     fn lex_selector_multiple_property_value() {
         let input = "(body background-color white color red)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("background-color".into()),
-            Token::String("white".into()),
-            Token::String("color".into()),
-            Token::String("red".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("background-color"),
+            string("white"),
+            string("color"),
+            string("red"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -279,67 +726,48 @@ mod tests {
     #[test]
     fn parse_selector_multiple_property_value() {
         let input = "(body background-color white color red)";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("body".into()),
-            rules: vec![
-                Rule {
-                    property: "background-color".into(),
-                    value: vec!["white".into()],
-                },
-                Rule {
-                    property: "color".into(),
-                    value: vec!["red".into()],
-                },
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("body"),
+            vec![
+                rule("background-color", &["white"]),
+                rule("color", &["red"]),
             ],
-            children: vec![],
-        }];
+            vec![],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn parse_selector_property_value_interleave_nested() {
         let input = "(body background-color white (p color blue) color red)";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("body".into()),
-            rules: vec![
-                Rule {
-                    property: "background-color".into(),
-                    value: vec!["white".into()],
-                },
-                Rule {
-                    property: "color".into(),
-                    value: vec!["red".into()],
-                },
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("body"),
+            vec![
+                rule("background-color", &["white"]),
+                rule("color", &["red"]),
             ],
-            children: vec![SExpr {
-                selector: Selector("p".into()),
-                rules: vec![Rule {
-                    property: "color".into(),
-                    value: vec!["blue".into()],
-                }],
-                children: vec![],
-            }],
-        }];
+            vec![s_expr(selector("p"), vec![rule("color", &["blue"])], vec![])],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn lex_selector_nested_selector_property_value() {
         let input = "(ul (li text-decoration none))";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("ul".into()),
-            Token::LParen,
-            Token::String("li".into()),
-            Token::String("text-decoration".into()),
-            Token::String("none".into()),
-            Token::RParen,
-            Token::RParen,
+            lparen(),
+            string("ul"),
+            lparen(),
+            string("li"),
+            string("text-decoration"),
+            string("none"),
+            rparen(),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -347,95 +775,71 @@ mod tests {
     #[test]
     fn parse_selector_nested_selector_property_value() {
         let input = "(ul (li text-decoration none))";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("ul".into()),
-            rules: vec![],
-            children: vec![SExpr {
-                selector: Selector("li".into()),
-                rules: vec![Rule {
-                    property: "text-decoration".into(),
-                    value: vec!["none".into()],
-                }],
-                children: vec![],
-            }],
-        }];
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("ul"),
+            vec![],
+            vec![s_expr(
+                selector("li"),
+                vec![rule("text-decoration", &["none"])],
+                vec![],
+            )],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn parse_selector_property_value_nested_selector_property_value() {
         let input = "(ul padding 0 (li text-decoration none))";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("ul".into()),
-            rules: vec![Rule {
-                property: "padding".into(),
-                value: vec!["0".into()],
-            }],
-            children: vec![SExpr {
-                selector: Selector("li".into()),
-                rules: vec![Rule {
-                    property: "text-decoration".into(),
-                    value: vec!["none".into()],
-                }],
-                children: vec![],
-            }],
-        }];
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("ul"),
+            vec![rule("padding", &["0"])],
+            vec![s_expr(
+                selector("li"),
+                vec![rule("text-decoration", &["none"])],
+                vec![],
+            )],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn parse_selector_multiple_property_value_nested_selector_property_value() {
         let input = "(ul padding 0 margin 0 (li padding-left 16px (a text-decoration none)))";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
-        let expected = vec![SExpr {
-            selector: Selector("ul".into()),
-            rules: vec![
-                Rule {
-                    property: "padding".into(),
-                    value: vec!["0".into()],
-                },
-                Rule {
-                    property: "margin".into(),
-                    value: vec!["0".into()],
-                },
-            ],
-            children: vec![SExpr {
-                selector: Selector("li".into()),
-                rules: vec![Rule {
-                    property: "padding-left".into(),
-                    value: vec!["16px".into()],
-                }],
-                children: vec![SExpr {
-                    selector: Selector("a".into()),
-                    rules: vec![Rule {
-                        property: "text-decoration".into(),
-                        value: vec!["none".into()],
-                    }],
-                    children: vec![],
-                }],
-            }],
-        }];
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
+        let expected = vec![s_expr(
+            selector("ul"),
+            vec![rule("padding", &["0"]), rule("margin", &["0"])],
+            vec![s_expr(
+                selector("li"),
+                vec![rule("padding-left", &["16px"])],
+                vec![s_expr(
+                    selector("a"),
+                    vec![rule("text-decoration", &["none"])],
+                    vec![],
+                )],
+            )],
+        )];
         assert_eq!(s_exprs, expected);
     }
 
     #[test]
     fn lex_selector_property_value_nested_selector() {
         let input = "(body color red (table))";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("color".into()),
-            Token::String("red".into()),
-            Token::LParen,
-            Token::String("table".into()),
-            Token::RParen,
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("color"),
+            string("red"),
+            lparen(),
+            string("table"),
+            rparen(),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -443,13 +847,13 @@ mod tests {
     #[test]
     fn lex_selector_hyphenated_property_parentheses_value() {
         let input = "(body background-color var(--text-color, red))";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("background-color".into()),
-            Token::String("var(--text-color, red)".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("background-color"),
+            string("var(--text-color, red)"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -457,13 +861,13 @@ mod tests {
     #[test]
     fn lex_selector_hyphenated_property_parentheses_value_continuing_string() {
         let input = "(body background-color var(--text-color, red)def)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("background-color".into()),
-            Token::String("var(--text-color, red)def".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("background-color"),
+            string("var(--text-color, red)def"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -471,13 +875,13 @@ mod tests {
     #[test]
     fn lex_pseudo_selector_property_value() {
         let input = "(a:hover text-decoration underline)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("a:hover".into()),
-            Token::String("text-decoration".into()),
-            Token::String("underline".into()),
-            Token::RParen,
+            lparen(),
+            string("a:hover"),
+            string("text-decoration"),
+            string("underline"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -485,18 +889,18 @@ mod tests {
     #[test]
     fn lex_multiple_selector_property_value() {
         let input = "(body color red)\n(p color blue)";
-        let tokens = lex(input.into()).unwrap();
+        let tokens = lex(input);
         let expected = vec![
-            Token::LParen,
-            Token::String("body".into()),
-            Token::String("color".into()),
-            Token::String("red".into()),
-            Token::RParen,
-            Token::LParen,
-            Token::String("p".into()),
-            Token::String("color".into()),
-            Token::String("blue".into()),
-            Token::RParen,
+            lparen(),
+            string("body"),
+            string("color"),
+            string("red"),
+            rparen(),
+            lparen(),
+            string("p"),
+            string("color"),
+            string("blue"),
+            rparen(),
         ];
         assert_eq!(tokens, expected);
     }
@@ -504,26 +908,122 @@ mod tests {
     #[test]
     fn parse_multiple_selector_property_value() {
         let input = "(body color red)\n(p color blue)";
-        let tokens = lex(input.into()).unwrap();
-        let s_exprs = parse(tokens);
+        let tokens = lex(input);
+        let s_exprs = parse(&tokens).unwrap();
         let expected = vec![
-            SExpr {
-                selector: Selector("body".into()),
-                rules: vec![Rule {
-                    property: "color".into(),
-                    value: vec!["red".into()],
-                }],
-                children: vec![],
-            },
-            SExpr {
-                selector: Selector("p".into()),
-                rules: vec![Rule {
-                    property: "color".into(),
-                    value: vec!["blue".into()],
-                }],
-                children: vec![],
-            },
+            s_expr(selector("body"), vec![rule("color", &["red"])], vec![]),
+            s_expr(selector("p"), vec![rule("color", &["blue"])], vec![]),
         ];
         assert_eq!(s_exprs, expected);
     }
+
+    #[test]
+    fn lex_records_byte_spans() {
+        let input = "(body color red)";
+        let tokens = lex(input);
+        let spans: Vec<(usize, usize)> = tokens.iter().map(|t| (t.span.start, t.span.end)).collect();
+        assert_eq!(spans, vec![(0, 1), (1, 5), (6, 11), (12, 15), (15, 16)]);
+    }
+
+    #[test]
+    fn parse_missing_value_errors_with_span() {
+        let input = "(body color)";
+        let tokens = lex(input);
+        let errors = parse(&tokens).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![ParseError {
+                message: "expected a value after property `color`".to_owned(),
+                span: Span { start: 6, end: 11 },
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_unterminated_paren_errors() {
+        let input = "(body color red";
+        let tokens = lex(input);
+        let errors = parse(&tokens).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unterminated parenthesis group");
+    }
+
+    #[test]
+    fn parse_surplus_closing_paren_errors_without_panicking() {
+        let input = "(body color red))";
+        let tokens = lex(input);
+        let errors = parse(&tokens).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unexpected closing parenthesis");
+    }
+
+    #[test]
+    fn parse_lone_closing_paren_errors_without_panicking() {
+        let input = ")";
+        let tokens = lex(input);
+        let errors = parse(&tokens).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unexpected closing parenthesis");
+    }
+
+    #[test]
+    fn to_stylesheet_minified_collapses_whitespace() {
+        use crate::StyleFormat;
+        let tokens = lex("(body color red margin (0 8px))");
+        let s_exprs = parse(&tokens).unwrap();
+        let mut out = String::new();
+        s_exprs[0].to_stylesheet("", StyleFormat::Minified, &mut out);
+        assert_eq!(out, "body{color:red;margin:0 8px}");
+    }
+
+    #[test]
+    fn defvar_substitutes_into_values() {
+        use crate::{StyleFormat, Stylesheet};
+        let tokens = lex("(defvar --brand #0af)\n(a color $--brand border (1px solid (var --brand)))");
+        let sheet = Stylesheet::parse(&tokens).unwrap();
+        assert_eq!(
+            sheet.to_stylesheet(StyleFormat::Minified),
+            "a{color:#0af;border:1px solid #0af}"
+        );
+    }
+
+    #[test]
+    fn base_sheet_is_overridden_by_child() {
+        use crate::{StyleFormat, Stylesheet};
+        let base_tokens = lex("(defvar --brand red)\n(a color $--brand)");
+        let base = Stylesheet::parse(&base_tokens).unwrap();
+        let child_tokens = lex("(defvar --brand blue)");
+        let child = Stylesheet::parse(&child_tokens).unwrap();
+        assert_eq!(
+            child.with_base(base).to_stylesheet(StyleFormat::Minified),
+            "a{color:blue}"
+        );
+    }
+
+    #[test]
+    fn parent_reference_attaches_without_descendant_space() {
+        use crate::StyleFormat;
+        let tokens = lex("(a color blue (&:hover text-decoration underline))");
+        let s_exprs = parse(&tokens).unwrap();
+        let mut out = String::new();
+        s_exprs[0].to_stylesheet("", StyleFormat::Minified, &mut out);
+        assert_eq!(out, "a{color:blue}a:hover{text-decoration:underline}");
+    }
+
+    #[test]
+    fn parent_reference_expands_each_comma_alternative() {
+        use crate::StyleFormat;
+        let tokens = lex("(a,b (&:hover color red))");
+        let s_exprs = parse(&tokens).unwrap();
+        let mut out = String::new();
+        s_exprs[0].to_stylesheet("", StyleFormat::Minified, &mut out);
+        assert_eq!(out, "a:hover,b:hover{color:red}");
+    }
+
+    #[test]
+    fn line_col_resolves_offset() {
+        let source = "(body\n  color red)";
+        assert_eq!(line_col(source, 0), (1, 1));
+        assert_eq!(line_col(source, 8), (2, 3));
+    }
 }
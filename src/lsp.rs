@@ -0,0 +1,337 @@
+//! Language server for `.csss` files.
+//!
+//! The server drives the same `lex`/`parse` pipeline as the CLI and wasm
+//! entry points and exposes it over LSP: it publishes diagnostics for the
+//! structural errors the parser reports, completes known CSS property names in
+//! a rule's property position, and renders the compiled CSS of the
+//! S-expression under the cursor on hover.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use css::{lex, parse, StyleFormat, Stylesheet};
+use lsp_server::{Connection, ExtractError, Message, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidOpenTextDocument, Notification, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, HoverRequest, Request as LspRequest};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, Hover, HoverContents, HoverParams, HoverProviderCapability,
+    InitializeParams, MarkupContent, MarkupKind, Position, PublishDiagnosticsParams, Range,
+    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+/// CSS property names offered as completions in a property position.
+const PROPERTIES: &[&str] = &[
+    "align-items",
+    "background",
+    "background-color",
+    "border",
+    "border-color",
+    "border-radius",
+    "box-shadow",
+    "color",
+    "display",
+    "flex",
+    "flex-direction",
+    "font-family",
+    "font-size",
+    "font-weight",
+    "gap",
+    "height",
+    "justify-content",
+    "line-height",
+    "margin",
+    "opacity",
+    "overflow",
+    "padding",
+    "position",
+    "text-align",
+    "text-decoration",
+    "transform",
+    "transition",
+    "width",
+    "z-index",
+];
+
+/// The in-memory mirror of every open document, keyed by URI. The parser
+/// borrows from the source text, so we cache the text itself and re-run the
+/// (cheap) lex/parse only for the document a request targets.
+#[derive(Default)]
+struct Documents {
+    texts: HashMap<Url, String>,
+}
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions::default()),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        ..ServerCapabilities::default()
+    };
+    let initialization_params = connection.initialize(serde_json::to_value(capabilities)?)?;
+    let _params: InitializeParams = serde_json::from_value(initialization_params)?;
+
+    main_loop(&connection)?;
+    io_threads.join()?;
+    Ok(())
+}
+
+fn main_loop(connection: &Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let mut documents = Documents::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                handle_request(connection, &documents, request)?;
+            }
+            Message::Notification(notification) => {
+                handle_notification(connection, &mut documents, notification)?;
+            }
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(
+    connection: &Connection,
+    documents: &Documents,
+    request: Request,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let response = match request.method.as_str() {
+        Completion::METHOD => {
+            let (id, params) = cast::<Completion>(request)?;
+            Response::new_ok(id, completion(documents, &params))
+        }
+        HoverRequest::METHOD => {
+            let (id, params) = cast::<HoverRequest>(request)?;
+            Response::new_ok(id, hover(documents, &params))
+        }
+        _ => return Ok(()),
+    };
+    connection.sender.send(Message::Response(response))?;
+    Ok(())
+}
+
+fn handle_notification(
+    connection: &Connection,
+    documents: &mut Documents,
+    notification: lsp_server::Notification,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params = cast_notification::<DidOpenTextDocument>(notification)?;
+            let uri = params.text_document.uri;
+            documents.texts.insert(uri.clone(), params.text_document.text);
+            publish_diagnostics(connection, documents, &uri)?;
+        }
+        DidChangeTextDocument::METHOD => {
+            let params = cast_notification::<DidChangeTextDocument>(notification)?;
+            let uri = params.text_document.uri;
+            // Full-sync mode: the last change carries the whole document.
+            if let Some(change) = params.content_changes.into_iter().last() {
+                documents.texts.insert(uri.clone(), change.text);
+            }
+            publish_diagnostics(connection, documents, &uri)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Lex and parse the document and publish any structural errors as
+/// diagnostics, clearing them when the document parses cleanly.
+fn publish_diagnostics(
+    connection: &Connection,
+    documents: &Documents,
+    uri: &Url,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let Some(text) = documents.texts.get(uri) else {
+        return Ok(());
+    };
+    let tokens = lex(text);
+    let diagnostics = match parse(&tokens) {
+        Ok(_) => vec![],
+        Err(errors) => errors
+            .iter()
+            .map(|error| Diagnostic {
+                range: Range {
+                    start: offset_to_position(text, error.span.start),
+                    end: offset_to_position(text, error.span.end),
+                },
+                severity: Some(DiagnosticSeverity::ERROR),
+                message: error.message.clone(),
+                ..Diagnostic::default()
+            })
+            .collect(),
+    };
+    let params = PublishDiagnosticsParams {
+        uri: uri.clone(),
+        diagnostics,
+        version: None,
+    };
+    let notification = lsp_server::Notification::new(
+        PublishDiagnostics::METHOD.to_owned(),
+        serde_json::to_value(params)?,
+    );
+    connection
+        .sender
+        .send(Message::Notification(notification))?;
+    Ok(())
+}
+
+fn completion(documents: &Documents, params: &CompletionParams) -> Option<CompletionResponse> {
+    let uri = &params.text_document_position.text_document.uri;
+    let text = documents.texts.get(uri)?;
+    let offset = position_to_offset(text, params.text_document_position.position);
+    if !in_property_position(text, offset) {
+        return None;
+    }
+    let items = PROPERTIES
+        .iter()
+        .map(|property| CompletionItem {
+            label: (*property).to_owned(),
+            kind: Some(CompletionItemKind::PROPERTY),
+            ..CompletionItem::default()
+        })
+        .collect();
+    Some(CompletionResponse::Array(items))
+}
+
+fn hover(documents: &Documents, params: &HoverParams) -> Option<Hover> {
+    let uri = &params
+        .text_document_position_params
+        .text_document
+        .uri;
+    let text = documents.texts.get(uri)?;
+    let offset = position_to_offset(text, params.text_document_position_params.position);
+    let tokens = lex(text);
+    let stylesheet = Stylesheet::parse(&tokens).ok()?;
+    let rendered = stylesheet.render_at(offset, StyleFormat::Pretty)?;
+    Some(Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: format!("```css\n{}```", rendered),
+        }),
+        range: None,
+    })
+}
+
+/// The position within an S-expression group relevant to deciding whether a
+/// string token is a property name: a group opens expecting its selector,
+/// then alternates between a property and its value.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GroupState {
+    Selector,
+    Property,
+    Value,
+}
+
+/// Decide whether `offset` sits on a property token: a string that begins a
+/// group's rules (immediately following the selector, or following a
+/// completed property/value pair). Value tokens are never property
+/// positions, however deeply a `(...)` value list nests.
+fn in_property_position(text: &str, offset: usize) -> bool {
+    let tokens = lex(text);
+    let mut groups: Vec<GroupState> = vec![];
+    let mut value_list_depth: u32 = 0;
+    for token in &tokens {
+        use css::TokenKind::*;
+        match token.kind {
+            LParen if value_list_depth > 0 => value_list_depth += 1,
+            LParen if groups.last() == Some(&GroupState::Value) => value_list_depth = 1,
+            LParen => groups.push(GroupState::Selector),
+            RParen if value_list_depth > 1 => value_list_depth -= 1,
+            RParen if value_list_depth == 1 => {
+                value_list_depth = 0;
+                if let Some(state) = groups.last_mut() {
+                    *state = GroupState::Property;
+                }
+            }
+            RParen => {
+                groups.pop();
+            }
+            String(_) if value_list_depth > 0 => {
+                if offset >= token.span.start && offset <= token.span.end {
+                    return false;
+                }
+            }
+            String(_) => {
+                let on_token = offset >= token.span.start && offset <= token.span.end;
+                let is_property = groups.last() == Some(&GroupState::Property);
+                if on_token {
+                    return is_property;
+                }
+                if let Some(state) = groups.last_mut() {
+                    *state = match state {
+                        GroupState::Selector | GroupState::Value => GroupState::Property,
+                        GroupState::Property => GroupState::Value,
+                    };
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Translate a zero-based LSP [`Position`] into a byte offset into `text`.
+///
+/// LSP counts `character` in UTF-16 code units (the server advertises no
+/// `positionEncoding`, so the client defaults to UTF-16), not Unicode scalar
+/// values, so characters outside the BMP count for two.
+fn position_to_offset(text: &str, position: Position) -> usize {
+    let mut line = 0;
+    let mut character = 0;
+    for (offset, c) in text.char_indices() {
+        if line == position.line && character == position.character {
+            return offset;
+        }
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16() as u32;
+        }
+    }
+    text.len()
+}
+
+/// Translate a byte offset into a zero-based LSP [`Position`], counting
+/// `character` in UTF-16 code units to match `position_to_offset`.
+fn offset_to_position(text: &str, offset: usize) -> Position {
+    let mut line = 0;
+    let mut character = 0;
+    for c in text[..offset].chars() {
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16() as u32;
+        }
+    }
+    Position { line, character }
+}
+
+fn cast<R>(request: Request) -> Result<(RequestId, R::Params), ExtractError<Request>>
+where
+    R: LspRequest,
+    R::Params: serde::de::DeserializeOwned,
+{
+    request.extract(R::METHOD)
+}
+
+fn cast_notification<N>(
+    notification: lsp_server::Notification,
+) -> Result<N::Params, ExtractError<lsp_server::Notification>>
+where
+    N: Notification,
+    N::Params: serde::de::DeserializeOwned,
+{
+    notification.extract(N::METHOD)
+}